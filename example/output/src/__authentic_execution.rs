@@ -2,46 +2,196 @@ pub mod authentic_execution {
     extern crate base64;
     extern crate reactive_crypto;
     extern crate reactive_net;
+    extern crate hkdf;
+    extern crate sha2;
+    extern crate x25519_dalek;
+    extern crate rand_core;
 
     use std::collections::HashMap;
     use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::thread;
     use std::net::TcpStream;
 
     use reactive_net::{ResultCode, CommandCode, ResultMessage, CommandMessage};
     use reactive_crypto::Encryption;
     use crate::__run::MODULE_KEY;
+    use crate::__run::{TRUST_MODE, SHARED_SECRET, TRUSTED_PEER_KEYS};
 
     mod connection {
         use reactive_crypto::Encryption;
+        use hkdf::Hkdf;
+        use sha2::Sha256;
+
+        // Width of the anti-replay sliding window, in bits of `recv_bitmap`
+        const WINDOW_SIZE : u64 = 64;
+
+        // Rekey once the counter crosses 3/4 of the original 16-bit nonce space, well before
+        // any (key, nonce) pair could realistically be reused. Configurable.
+        const REKEY_THRESHOLD : u64 = 49_152;
+
+        // Bounds how many generations a single incoming packet can ask us to catch up across.
+        // A legitimate peer is never more than one generation ahead in practice (rekeys happen
+        // every REKEY_THRESHOLD messages); this just stops an unauthenticated packet with a
+        // far-future generation field from forcing thousands of HKDF derivations.
+        const MAX_GENERATION_SKIP : u16 = 16;
 
         pub struct Connection {
             index : u16,
-            nonce : u16,
+            counter : u64,
+            generation : u16,
+            recv_highest : u64,
+            recv_bitmap : u64,
             key : Vec<u8>,
-            encryption : Encryption
+            encryption : Encryption,
+            // the peer's static public key, set when the connection was established via the
+            // public-key handshake rather than an EM-brokered `set_key`
+            peer_identity : Option<[u8; 32]>
         }
 
         impl Connection {
-            pub fn new(index : u16, nonce : u16, key : Vec<u8>, encryption : Encryption) -> Connection {
+            pub fn new(index : u16, key : Vec<u8>, encryption : Encryption) -> Connection {
                 Connection {
                     index,
-                    nonce,
+                    counter: 0,
+                    generation: 0,
+                    recv_highest: 0,
+                    recv_bitmap: 0,
                     key,
-                    encryption
+                    encryption,
+                    peer_identity: None
                 }
             }
 
+            /// Builds a connection established through the public-key handshake, which also
+            /// authenticates the peer's static identity key.
+            pub fn new_with_peer(index : u16, key : Vec<u8>, encryption : Encryption, peer_identity : [u8; 32]) -> Connection {
+                let mut conn = Connection::new(index, key, encryption);
+                conn.peer_identity = Some(peer_identity);
+                conn
+            }
+
             pub fn get_index(&self) -> u16 {
                 self.index
             }
 
-            pub fn get_nonce(&self) -> u16 {
-                self.nonce
+            #[allow(dead_code)] // exposed for callers that need to audit which peer a connection was authenticated against
+            pub fn get_peer_identity(&self) -> Option<&[u8; 32]> {
+                self.peer_identity.as_ref()
+            }
+
+            pub fn get_generation(&self) -> u16 {
+                self.generation
+            }
+
+            /// Returns the counter value to use as the AEAD nonce for the next outgoing message,
+            /// and advances it. The counter is sent alongside the ciphertext so the receiver can
+            /// validate it without relying on in-order delivery. Transparently rekeys first if
+            /// the counter has crossed the rekey threshold.
+            pub fn next_counter(&mut self) -> u64 {
+                if self.counter >= REKEY_THRESHOLD {
+                    self.rekey();
+                }
+
+                let counter = self.counter;
+                self.counter += 1;
+                counter
+            }
+
+            /// Derives the next session key from the current one via HKDF-SHA256, bumps the
+            /// generation counter, and resets the nonce counter, so the (key, nonce) pair never
+            /// repeats even over a long-lived connection.
+            fn rekey(&mut self) {
+                self.generation = self.generation.wrapping_add(1);
+                self.key = Self::derive_rekeyed_key(&self.key, self.generation);
+                self.counter = 0;
             }
 
-            pub fn increment_nonce(&mut self) {
-                self.nonce += 1;
+            /// One deterministic HKDF-SHA256 step from `key` to the key for `generation`, shared
+            /// by the local rekey path and the peer-catch-up path below so both derive identically.
+            fn derive_rekeyed_key(key : &[u8], generation : u16) -> Vec<u8> {
+                let mut salt = b"ae-rekey".to_vec();
+                salt.extend_from_slice(&generation.to_be_bytes());
+
+                let hkdf = Hkdf::<Sha256>::new(Some(&salt), key);
+                let mut next_key = vec![0u8; key.len()];
+                hkdf.expand(&[], &mut next_key).expect("HKDF output length is always valid for an existing key size");
+                next_key
+            }
+
+            /// Computes (without committing) the key the peer would be using at
+            /// `target_generation`, by repeating the same deterministic derivation locally.
+            /// Returns `None` if `target_generation` is behind the connection's own generation.
+            /// The caller must only commit this via `commit_generation` once the packet that
+            /// claimed `target_generation` has been authenticated with the returned key --
+            /// otherwise an unauthenticated packet with a bogus future generation could force
+            /// unbounded HKDF work and desync the connection.
+            pub fn derive_key_for_generation(&self, target_generation : u16) -> Option<Vec<u8>> {
+                if target_generation < self.generation {
+                    return None;
+                }
+
+                if target_generation - self.generation > MAX_GENERATION_SKIP {
+                    return None;
+                }
+
+                let mut key = self.key.clone();
+                let mut generation = self.generation;
+
+                while generation != target_generation {
+                    generation = generation.wrapping_add(1);
+                    key = Self::derive_rekeyed_key(&key, generation);
+                }
+
+                Some(key)
+            }
+
+            /// Commits a generation/key advance that `derive_key_for_generation` computed, once
+            /// the corresponding packet has decrypted successfully under `key`. The replay window
+            /// is reset, since the peer's nonce counter restarted at 0 too.
+            pub fn commit_generation(&mut self, target_generation : u16, key : Vec<u8>) {
+                self.generation = target_generation;
+                self.key = key;
+                self.counter = 0;
+                self.recv_highest = 0;
+                self.recv_bitmap = 0;
+            }
+
+            /// Checks, without recording anything, whether `counter` would be accepted by an
+            /// IPsec/WireGuard-style sliding window: `false` for counters that are too old
+            /// (fallen out of the window) or that have already been seen. The caller must only
+            /// record the counter via `commit_replay_window` once it has actually authenticated --
+            /// otherwise a forged packet could advance the window and make every legitimate
+            /// in-order packet after it look replayed.
+            pub fn check_replay_window(&self, counter : u64) -> bool {
+                if counter > self.recv_highest {
+                    return true;
+                }
+
+                if counter.checked_add(WINDOW_SIZE).map_or(true, |bound| bound <= self.recv_highest) {
+                    return false; // too old
+                }
+
+                let offset = self.recv_highest - counter;
+                let mask = 1u64 << offset;
+                self.recv_bitmap & mask == 0
+            }
+
+            /// Records a counter that `check_replay_window` already accepted. Must only be called
+            /// once the corresponding packet has been authenticated, so the window can't be
+            /// desynced by unauthenticated input.
+            pub fn commit_replay_window(&mut self, counter : u64) {
+                if counter > self.recv_highest {
+                    let shift = counter - self.recv_highest;
+                    self.recv_bitmap = if shift >= WINDOW_SIZE { 0 } else { self.recv_bitmap << shift };
+                    self.recv_bitmap |= 1;
+                    self.recv_highest = counter;
+                    return;
+                }
+
+                let offset = self.recv_highest - counter;
+                let mask = 1u64 << offset;
+                self.recv_bitmap |= mask;
             }
 
             pub fn get_key(&self) -> &Vec<u8> {
@@ -52,6 +202,450 @@ pub mod authentic_execution {
                 &self.encryption
             }
         }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn conn_at(generation : u16, recv_highest : u64, recv_bitmap : u64) -> Connection {
+                Connection {
+                    index: 0,
+                    counter: 0,
+                    generation,
+                    recv_highest,
+                    recv_bitmap,
+                    key: vec![0u8; 32],
+                    encryption: Encryption::Aes,
+                    peer_identity: None
+                }
+            }
+
+            #[test]
+            fn replay_window_accepts_new_highest_counter() {
+                let conn = conn_at(0, 10, 0);
+                assert!(conn.check_replay_window(11));
+                assert!(conn.check_replay_window(1000));
+            }
+
+            #[test]
+            fn replay_window_rejects_already_seen_bit() {
+                let mut conn = conn_at(0, 10, 0);
+                assert!(conn.check_replay_window(9));
+                conn.commit_replay_window(9);
+                assert!(!conn.check_replay_window(9));
+            }
+
+            #[test]
+            fn replay_window_boundary_just_inside_and_just_outside() {
+                let conn = conn_at(0, 100, 0);
+                // counter + WINDOW_SIZE == recv_highest + 1 is the oldest counter still in-window
+                assert!(conn.check_replay_window(100 - WINDOW_SIZE + 1));
+                // counter + WINDOW_SIZE <= recv_highest is too old
+                assert!(!conn.check_replay_window(100 - WINDOW_SIZE));
+            }
+
+            #[test]
+            fn replay_window_large_jump_resets_bitmap() {
+                let mut conn = conn_at(0, 10, u64::MAX);
+                assert!(conn.check_replay_window(10 + WINDOW_SIZE));
+                conn.commit_replay_window(10 + WINDOW_SIZE);
+                // the jump was >= WINDOW_SIZE, so every bit from the old window must be gone,
+                // leaving only the bit for the counter just committed
+                assert_eq!(conn.recv_bitmap, 1);
+                assert_eq!(conn.recv_highest, 10 + WINDOW_SIZE);
+            }
+
+            #[test]
+            fn replay_window_commit_then_reject_replay_of_same_counter() {
+                let mut conn = conn_at(0, 0, 0);
+                assert!(conn.check_replay_window(5));
+                conn.commit_replay_window(5);
+                assert!(!conn.check_replay_window(5));
+            }
+
+            #[test]
+            fn derive_key_for_generation_same_generation_is_a_no_op() {
+                let conn = conn_at(3, 0, 0);
+                let derived = conn.derive_key_for_generation(3).unwrap();
+                assert_eq!(derived, conn.key);
+            }
+
+            #[test]
+            fn derive_key_for_generation_respects_max_skip_boundary() {
+                let conn = conn_at(0, 0, 0);
+                assert!(conn.derive_key_for_generation(MAX_GENERATION_SKIP).is_some());
+                assert!(conn.derive_key_for_generation(MAX_GENERATION_SKIP + 1).is_none());
+            }
+
+            #[test]
+            fn derive_key_for_generation_rejects_stale_generation() {
+                let conn = conn_at(5, 0, 0);
+                assert!(conn.derive_key_for_generation(4).is_none());
+            }
+
+            #[test]
+            fn derive_key_for_generation_is_deterministic() {
+                let conn_a = conn_at(0, 0, 0);
+                let conn_b = conn_at(0, 0, 0);
+                assert_eq!(conn_a.key, conn_b.key);
+
+                let derived_a = conn_a.derive_key_for_generation(3).unwrap();
+                let derived_b = conn_b.derive_key_for_generation(3).unwrap();
+                assert_eq!(derived_a, derived_b, "two independent peers must derive the same key for the same generation");
+            }
+
+            #[test]
+            fn commit_generation_advances_state_and_resets_window() {
+                let mut conn = conn_at(0, 42, 0xFF);
+                let derived = conn.derive_key_for_generation(2).unwrap();
+                conn.commit_generation(2, derived.clone());
+
+                assert_eq!(conn.generation, 2);
+                assert_eq!(conn.key, derived);
+                assert_eq!(conn.counter, 0);
+                assert_eq!(conn.recv_highest, 0);
+                assert_eq!(conn.recv_bitmap, 0);
+            }
+        }
+    }
+
+    /// Direct, EM-blind connection establishment: each module owns an X25519 static identity key
+    /// pair and a set of peers it trusts, and negotiates session keys with a Noise-style
+    /// handshake (ephemeral-ephemeral DH plus both static-ephemeral DHs, mutually authenticated).
+    /// The event manager only ever sees the handshake and data ciphertexts, never a key.
+    mod handshake {
+        use hkdf::Hkdf;
+        use sha2::Sha256;
+        use rand_core::OsRng;
+        use x25519_dalek::{ReusableSecret, StaticSecret, PublicKey};
+
+        /// How a module decides which peer static public keys to trust.
+        pub enum TrustConfig {
+            /// The static key pair is derived deterministically from a secret shared out-of-band
+            /// with the peer(s); every module sharing that secret derives the same key pair, so
+            /// the single resulting public key is the only one trusted.
+            SharedSecret([u8; 32]),
+            /// The static key pair is random per module; only the peer public keys explicitly
+            /// listed in config are trusted.
+            ExplicitTrust(Vec<[u8; 32]>)
+        }
+
+        pub struct Identity {
+            static_secret : StaticSecret,
+            trust : TrustConfig
+        }
+
+        impl Identity {
+            pub fn new(trust : TrustConfig) -> Identity {
+                let static_secret = match &trust {
+                    TrustConfig::SharedSecret(secret) => StaticSecret::from(derive_seed(secret)),
+                    TrustConfig::ExplicitTrust(_) => StaticSecret::new(OsRng)
+                };
+
+                Identity { static_secret, trust }
+            }
+
+            pub fn public_key(&self) -> PublicKey {
+                PublicKey::from(&self.static_secret)
+            }
+
+            pub fn static_diffie_hellman(&self, peer_pub : &PublicKey) -> x25519_dalek::SharedSecret {
+                self.static_secret.diffie_hellman(peer_pub)
+            }
+
+            pub fn is_trusted(&self, peer_static_pub : &[u8; 32]) -> bool {
+                match &self.trust {
+                    TrustConfig::SharedSecret(secret) => {
+                        let expected = PublicKey::from(&StaticSecret::from(derive_seed(secret)));
+                        peer_static_pub == expected.as_bytes()
+                    },
+                    TrustConfig::ExplicitTrust(trusted) => trusted.iter().any(|k| k == peer_static_pub)
+                }
+            }
+        }
+
+        fn derive_seed(secret : &[u8; 32]) -> [u8; 32] {
+            let hkdf = Hkdf::<Sha256>::new(Some(b"ae-handshake-static-key"), secret);
+            let mut seed = [0u8; 32];
+            hkdf.expand(&[], &mut seed).expect("32 is a valid HKDF-SHA256 output length");
+            seed
+        }
+
+        /// An in-progress handshake initiated by this module, waiting for the peer's response.
+        pub struct PendingHandshake {
+            pub index : u16,
+            pub ephemeral_secret : ReusableSecret,
+            pub peer_static_pub : [u8; 32],
+            pub encryption : reactive_crypto::Encryption
+        }
+
+        // AEAD session keys derived from the handshake are always this many bytes (AES-256 / equivalent)
+        pub const SESSION_KEY_LEN : usize = 32;
+
+        /// Derives the session key both sides converge on: HKDF-SHA256 over the concatenation of
+        /// the ephemeral-ephemeral DH and both static-ephemeral DHs (Noise `ee + se + es`).
+        pub fn derive_session_key(ee : &[u8], se : &[u8], es : &[u8]) -> Vec<u8> {
+            let mut ikm = Vec::with_capacity(ee.len() + se.len() + es.len());
+            ikm.extend_from_slice(ee);
+            ikm.extend_from_slice(se);
+            ikm.extend_from_slice(es);
+
+            let hkdf = Hkdf::<Sha256>::new(Some(b"ae-handshake-session-key"), &ikm);
+            let mut key = vec![0u8; SESSION_KEY_LEN];
+            hkdf.expand(&[], &mut key).expect("HKDF output length is always valid for a 32 byte AEAD key");
+            key
+        }
+    }
+
+    /// Tamper-evident, append-only log of every accepted `handle_input` and emitted
+    /// `handle_output`, kept as a Merkle mountain range: a forest of perfect binary peak trees
+    /// (one per set bit of the leaf count) merged bottom-up on each append, so appending is
+    /// O(log n) and nothing is ever rebuilt from scratch. A verifier holding a signed root can
+    /// check which events the enclave processed, and in what order, after the fact.
+    mod audit_log {
+        use sha2::{Sha256, Digest};
+
+        #[derive(Clone, Copy)]
+        pub enum Direction {
+            Input,
+            Output
+        }
+
+        fn hash_pair(left : &[u8; 32], right : &[u8; 32]) -> [u8; 32] {
+            let mut hasher = Sha256::new();
+            hasher.update(left);
+            hasher.update(right);
+            hasher.finalize().into()
+        }
+
+        /// Hashes one event: conn_id, direction, generation, counter, and a digest of the plaintext,
+        /// so the proof attests to exactly which bytes were processed without storing them.
+        fn leaf_hash(conn_id : u16, direction : Direction, generation : u16, counter : u64, plaintext : &[u8]) -> [u8; 32] {
+            let plaintext_hash : [u8; 32] = Sha256::digest(plaintext).into();
+
+            let mut hasher = Sha256::new();
+            hasher.update(conn_id.to_be_bytes());
+            hasher.update([match direction { Direction::Input => 0u8, Direction::Output => 1u8 }]);
+            hasher.update(generation.to_be_bytes());
+            hasher.update(counter.to_be_bytes());
+            hasher.update(plaintext_hash);
+            hasher.finalize().into()
+        }
+
+        enum Node {
+            Leaf([u8; 32]),
+            Internal { hash : [u8; 32], height : u32, left : Box<Node>, right : Box<Node> }
+        }
+
+        impl Node {
+            fn hash(&self) -> [u8; 32] {
+                match self {
+                    Node::Leaf(h) => *h,
+                    Node::Internal { hash, .. } => *hash
+                }
+            }
+
+            fn height(&self) -> u32 {
+                match self {
+                    Node::Leaf(_) => 0,
+                    Node::Internal { height, .. } => *height
+                }
+            }
+
+            fn leaf_count(&self) -> u64 {
+                1u64 << self.height()
+            }
+        }
+
+        /// One step of an inclusion proof: the sibling's hash, and whether that sibling sits to
+        /// the right of the path (so the verifier knows which side to hash it on).
+        pub struct ProofStep {
+            pub sibling_hash : [u8; 32],
+            pub sibling_is_right : bool
+        }
+
+        /// Proves that a given leaf is included under the current root: the path of sibling
+        /// hashes up to its peak, plus the other peaks needed to re-fold the global root.
+        pub struct InclusionProof {
+            pub path : Vec<ProofStep>,
+            pub peak_hashes : Vec<[u8; 32]>,
+            pub own_peak_index : usize
+        }
+
+        pub struct MerkleMountainRange {
+            peaks : Vec<Node>,
+            leaf_count : u64
+        }
+
+        impl MerkleMountainRange {
+            pub fn new() -> MerkleMountainRange {
+                MerkleMountainRange { peaks: Vec::new(), leaf_count: 0 }
+            }
+
+            pub fn leaf_count(&self) -> u64 {
+                self.leaf_count
+            }
+
+            /// Appends a new leaf, merging equal-height peaks bottom-up (same rule as incrementing
+            /// a binary counter), which keeps the number of peaks at O(log n).
+            pub fn append(&mut self, leaf : [u8; 32]) {
+                let mut carry = Node::Leaf(leaf);
+
+                while let Some(top) = self.peaks.last() {
+                    if top.height() != carry.height() {
+                        break;
+                    }
+
+                    let left = self.peaks.pop().unwrap();
+                    let hash = hash_pair(&left.hash(), &carry.hash());
+                    let height = left.height() + 1;
+                    carry = Node::Internal { hash, height, left: Box::new(left), right: Box::new(carry) };
+                }
+
+                self.peaks.push(carry);
+                self.leaf_count += 1;
+            }
+
+            /// The current root: the peaks, bagged left to right into a single hash.
+            pub fn root(&self) -> [u8; 32] {
+                let mut peaks = self.peaks.iter();
+                let mut acc = match peaks.next() {
+                    Some(p) => p.hash(),
+                    None => [0u8; 32]
+                };
+
+                for p in peaks {
+                    acc = hash_pair(&acc, &p.hash());
+                }
+
+                acc
+            }
+
+            /// Builds an inclusion proof for the `leaf_index`-th leaf ever appended (0-based).
+            pub fn prove(&self, leaf_index : u64) -> Option<InclusionProof> {
+                if leaf_index >= self.leaf_count {
+                    return None;
+                }
+
+                let mut leaves_before = 0u64;
+                for (peak_index, peak) in self.peaks.iter().enumerate() {
+                    let peak_leaves = peak.leaf_count();
+
+                    if leaf_index < leaves_before + peak_leaves {
+                        let local_index = leaf_index - leaves_before;
+                        let mut path = Vec::new();
+                        descend(peak, local_index, peak.height(), &mut path);
+                        path.reverse(); // leaf-to-root order
+
+                        let peak_hashes = self.peaks.iter().map(|p| p.hash()).collect();
+                        return Some(InclusionProof { path, peak_hashes, own_peak_index: peak_index });
+                    }
+
+                    leaves_before += peak_leaves;
+                }
+
+                None // unreachable: leaf_count is the sum of all peaks' leaf counts
+            }
+        }
+
+        fn descend(node : &Node, local_index : u64, height : u32, path : &mut Vec<ProofStep>) {
+            if height == 0 {
+                return;
+            }
+
+            if let Node::Internal { left, right, .. } = node {
+                let half = 1u64 << (height - 1);
+
+                if local_index < half {
+                    path.push(ProofStep { sibling_hash: right.hash(), sibling_is_right: true });
+                    descend(left, local_index, height - 1, path);
+                } else {
+                    path.push(ProofStep { sibling_hash: left.hash(), sibling_is_right: false });
+                    descend(right, local_index - half, height - 1, path);
+                }
+            }
+        }
+
+        /// Records one accepted input or emitted output in the global audit log.
+        pub fn append(conn_id : u16, direction : Direction, generation : u16, counter : u64, plaintext : &[u8]) {
+            let leaf = leaf_hash(conn_id, direction, generation, counter, plaintext);
+            super::AUDIT_LOG.lock().unwrap().append(leaf);
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn leaf(n : u64) -> [u8; 32] {
+                leaf_hash(0, Direction::Input, 0, n, &n.to_be_bytes())
+            }
+
+            /// Re-derives the root from a leaf and its proof exactly as a verifier would: walk
+            /// the path up to the leaf's own peak, then bag all peaks left to right.
+            fn recompute_root(leaf : [u8; 32], proof : &InclusionProof) -> [u8; 32] {
+                let mut acc = leaf;
+                for step in &proof.path {
+                    acc = if step.sibling_is_right {
+                        hash_pair(&acc, &step.sibling_hash)
+                    } else {
+                        hash_pair(&step.sibling_hash, &acc)
+                    };
+                }
+
+                assert_eq!(acc, proof.peak_hashes[proof.own_peak_index], "path didn't fold up to the claimed peak");
+
+                let mut peaks = proof.peak_hashes.iter();
+                let mut bagged = *peaks.next().expect("a valid proof always has at least one peak");
+                for p in peaks {
+                    bagged = hash_pair(&bagged, p);
+                }
+
+                bagged
+            }
+
+            #[test]
+            fn proof_reproduces_root_for_every_leaf() {
+                // covers single-peak, multi-peak, and power-of-two boundary shapes
+                for n in 1u64..=20 {
+                    let mut mmr = MerkleMountainRange::new();
+                    let leaves : Vec<[u8; 32]> = (0..n).map(leaf).collect();
+
+                    for l in &leaves {
+                        mmr.append(*l);
+                    }
+
+                    let root = mmr.root();
+
+                    for i in 0..n {
+                        let proof = mmr.prove(i).unwrap_or_else(|| panic!("no proof for leaf {} of {}", i, n));
+                        assert_eq!(recompute_root(leaves[i as usize], &proof), root, "mismatch for leaf {} of {}", i, n);
+                    }
+                }
+            }
+
+            #[test]
+            fn prove_rejects_out_of_range_index() {
+                let mut mmr = MerkleMountainRange::new();
+                assert!(mmr.prove(0).is_none());
+
+                mmr.append(leaf(0));
+                mmr.append(leaf(1));
+                assert!(mmr.prove(2).is_none());
+            }
+
+            #[test]
+            fn root_changes_as_leaves_are_appended() {
+                let mut mmr = MerkleMountainRange::new();
+                let empty_root = mmr.root();
+
+                mmr.append(leaf(0));
+                let first_root = mmr.root();
+                assert_ne!(empty_root, first_root);
+
+                mmr.append(leaf(1));
+                assert_ne!(first_root, mmr.root());
+            }
+        }
     }
 
     #[allow(dead_code)]
@@ -69,6 +663,16 @@ pub mod authentic_execution {
         val.to_be_bytes()
     }
 
+    #[allow(dead_code)]
+    pub fn data_to_u64(data : &[u8]) -> u64 {
+        u64::from_be_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]])
+    }
+
+    #[allow(dead_code)]
+    pub fn u64_to_data(val : u64) -> [u8; 8] {
+        val.to_be_bytes()
+    }
+
     pub fn success(data : Option<Vec<u8>>) -> ResultMessage {
         ResultMessage::new(ResultCode::Ok, data)
     }
@@ -162,12 +766,196 @@ pub mod authentic_execution {
             None    => return failure(ResultCode::CryptoError, None)
         };
 
-        let conn = connection::Connection::new(data_to_u16(index), 0, key, enc_type);
+        let conn = connection::Connection::new(data_to_u16(index), key, enc_type);
         add_connection(data_to_u16(conn_id), conn);
 
         success(None)
     }
 
+    pub fn handshake_init_wrapper(data : &[u8]) -> ResultMessage {
+        // The payload is: [index - conn_id - encryption_type - peer_static_pub]
+        debug!("ENTRYPOINT: handshake_init");
+
+        if data.len() < 37 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        handshake_init(data_to_u16(&data[0..2]), data_to_u16(&data[2..4]), data[4], &data[5..37])
+    }
+
+    /// Starts a handshake as the initiator: generate an ephemeral key pair, remember it while we
+    /// wait for the peer's response, and hand the EM our ephemeral and static public keys
+    /// (`[ephemeral_pub - static_pub]`) to forward blindly to the peer.
+    fn handshake_init(index : u16, conn_id : u16, enc : u8, peer_static_pub : &[u8]) -> ResultMessage {
+        let identity = match &*IDENTITY {
+            Ok(i) => i,
+            Err(_) => return failure(ResultCode::InternalError, None)
+        };
+
+        let mut peer_static_pub_arr = [0u8; 32];
+        peer_static_pub_arr.copy_from_slice(peer_static_pub);
+
+        if !identity.is_trusted(&peer_static_pub_arr) {
+            return failure(ResultCode::BadRequest, None)
+        }
+
+        let enc_type = match Encryption::from_u8(enc) {
+            Some(e) => e,
+            None    => return failure(ResultCode::CryptoError, None)
+        };
+
+        let ephemeral_secret = x25519_dalek::ReusableSecret::new(rand_core::OsRng);
+        let ephemeral_pub = x25519_dalek::PublicKey::from(&ephemeral_secret);
+
+        PENDING_HANDSHAKES.lock().unwrap().insert(conn_id, handshake::PendingHandshake {
+            index,
+            ephemeral_secret,
+            peer_static_pub: peer_static_pub_arr,
+            encryption: enc_type
+        });
+
+        let mut out = Vec::with_capacity(64);
+        out.extend_from_slice(ephemeral_pub.as_bytes());
+        out.extend_from_slice(identity.public_key().as_bytes());
+        success(Some(out))
+    }
+
+    pub fn handshake_respond_wrapper(data : &[u8]) -> ResultMessage {
+        // The payload is: [index - conn_id - encryption_type - peer_ephemeral_pub - peer_static_pub]
+        debug!("ENTRYPOINT: handshake_respond");
+
+        if data.len() < 69 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        handshake_respond(data_to_u16(&data[0..2]), data_to_u16(&data[2..4]), data[4], &data[5..37], &data[37..69])
+    }
+
+    /// Responds to a handshake as the responder: the peer's ephemeral key and own static secret
+    /// are already enough to derive the full session key, so the connection can be established
+    /// immediately, without waiting on a further message.
+    fn handshake_respond(index : u16, conn_id : u16, enc : u8, peer_ephemeral_pub : &[u8], peer_static_pub : &[u8]) -> ResultMessage {
+        let identity = match &*IDENTITY {
+            Ok(i) => i,
+            Err(_) => return failure(ResultCode::InternalError, None)
+        };
+
+        let mut peer_static_pub_arr = [0u8; 32];
+        peer_static_pub_arr.copy_from_slice(peer_static_pub);
+
+        if !identity.is_trusted(&peer_static_pub_arr) {
+            return failure(ResultCode::BadRequest, None)
+        }
+
+        let enc_type = match Encryption::from_u8(enc) {
+            Some(e) => e,
+            None    => return failure(ResultCode::CryptoError, None)
+        };
+
+        let mut peer_ephemeral_pub_arr = [0u8; 32];
+        peer_ephemeral_pub_arr.copy_from_slice(peer_ephemeral_pub);
+        let peer_ephemeral_pub = x25519_dalek::PublicKey::from(peer_ephemeral_pub_arr);
+        let peer_static_pub = x25519_dalek::PublicKey::from(peer_static_pub_arr);
+
+        let my_ephemeral_secret = x25519_dalek::ReusableSecret::new(rand_core::OsRng);
+        let my_ephemeral_pub = x25519_dalek::PublicKey::from(&my_ephemeral_secret);
+
+        let ee = my_ephemeral_secret.diffie_hellman(&peer_ephemeral_pub);
+        let se = my_ephemeral_secret.diffie_hellman(&peer_static_pub); // peer's static x our ephemeral
+        let es = identity.static_diffie_hellman(&peer_ephemeral_pub); // our static x peer's ephemeral
+
+        let key = handshake::derive_session_key(ee.as_bytes(), se.as_bytes(), es.as_bytes());
+
+        let conn = connection::Connection::new_with_peer(index, key, enc_type, peer_static_pub_arr);
+        add_connection(conn_id, conn);
+
+        success(Some(my_ephemeral_pub.as_bytes().to_vec()))
+    }
+
+    pub fn handshake_finalize_wrapper(data : &[u8]) -> ResultMessage {
+        // The payload is: [conn_id - peer_ephemeral_pub]
+        debug!("ENTRYPOINT: handshake_finalize");
+
+        if data.len() < 34 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        handshake_finalize(data_to_u16(&data[0..2]), &data[2..34])
+    }
+
+    /// Finishes the handshake as the initiator once the responder's ephemeral public key comes
+    /// back, deriving the same session key and establishing the connection.
+    fn handshake_finalize(conn_id : u16, peer_ephemeral_pub : &[u8]) -> ResultMessage {
+        let identity = match &*IDENTITY {
+            Ok(i) => i,
+            Err(_) => return failure(ResultCode::InternalError, None)
+        };
+
+        let pending = match PENDING_HANDSHAKES.lock().unwrap().remove(&conn_id) {
+            Some(p) => p,
+            None => return failure(ResultCode::BadRequest, None)
+        };
+
+        let mut peer_ephemeral_pub_arr = [0u8; 32];
+        peer_ephemeral_pub_arr.copy_from_slice(peer_ephemeral_pub);
+        let peer_ephemeral_pub = x25519_dalek::PublicKey::from(peer_ephemeral_pub_arr);
+
+        let ee = pending.ephemeral_secret.diffie_hellman(&peer_ephemeral_pub);
+        let se = identity.static_diffie_hellman(&peer_ephemeral_pub); // our static x peer's ephemeral
+        let es = pending.ephemeral_secret.diffie_hellman(&x25519_dalek::PublicKey::from(pending.peer_static_pub)); // peer's static x our ephemeral
+
+        let key = handshake::derive_session_key(ee.as_bytes(), se.as_bytes(), es.as_bytes());
+
+        let conn = connection::Connection::new_with_peer(pending.index, key, pending.encryption, pending.peer_static_pub);
+        add_connection(conn_id, conn);
+
+        success(None)
+    }
+
+    pub fn audit_root_wrapper(_data : &[u8]) -> ResultMessage {
+        // No payload: returns the current audit log state
+        debug!("ENTRYPOINT: audit_root");
+
+        let log = AUDIT_LOG.lock().unwrap();
+
+        let mut out = Vec::with_capacity(40);
+        out.extend_from_slice(&log.root());
+        out.extend_from_slice(&log.leaf_count().to_be_bytes());
+        success(Some(out))
+    }
+
+    pub fn audit_proof_wrapper(data : &[u8]) -> ResultMessage {
+        // The payload is: [leaf_index]
+        debug!("ENTRYPOINT: audit_proof");
+
+        if data.len() < 8 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        let leaf_index = data_to_u64(data);
+
+        let log = AUDIT_LOG.lock().unwrap();
+        let proof = match log.prove(leaf_index) {
+            Some(p) => p,
+            None => return failure(ResultCode::BadRequest, None)
+        };
+
+        // Serialized as: [own_peak_index(2) - num_peaks(2) - peak_hashes - path_len(2) - path entries(is_right(1) - hash(32))]
+        let mut out = Vec::with_capacity(4 + proof.peak_hashes.len() * 32 + 2 + proof.path.len() * 33);
+        out.extend_from_slice(&u16_to_data(proof.own_peak_index as u16));
+        out.extend_from_slice(&u16_to_data(proof.peak_hashes.len() as u16));
+        for peak in &proof.peak_hashes {
+            out.extend_from_slice(peak);
+        }
+        out.extend_from_slice(&u16_to_data(proof.path.len() as u16));
+        for step in &proof.path {
+            out.push(step.sibling_is_right as u8);
+            out.extend_from_slice(&step.sibling_hash);
+        }
+
+        success(Some(out))
+    }
+
     pub fn handle_input_wrapper(data : &[u8]) -> ResultMessage  {
         // The payload is: [index - payload]
         debug!("ENTRYPOINT: handle_input");
@@ -181,6 +969,15 @@ pub mod authentic_execution {
 
     fn handle_input(conn_id : u16, payload : &[u8]) -> ResultMessage {
         // the index is not associated data because it is not sent by the `from` module, but by the event manager
+        // the payload is: [generation - counter - ciphertext], the counter being the AEAD nonce used by the sender
+
+        if payload.len() < 10 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        let generation = data_to_u16(payload);
+        let counter = data_to_u64(&payload[2..10]);
+        let ciphertext = &payload[10..];
 
         let mut map = CONNECTIONS.lock().unwrap();
         let conn = match map.get_mut(&conn_id) {
@@ -188,13 +985,39 @@ pub mod authentic_execution {
             None => return failure(ResultCode::BadRequest, None)
         };
 
-        let nonce = conn.get_nonce();
-        let data = match reactive_crypto::decrypt(payload, conn.get_key(), &u16_to_data(nonce), conn.get_encryption()) {
+        if generation < conn.get_generation() {
+            return failure(ResultCode::CryptoError, None)
+        }
+
+        // the peer may have moved on to the next generation(s); derive (but don't yet commit)
+        // the key it would now be using, so a forged packet can't force the generation/key to
+        // advance before it has actually been authenticated below
+        let candidate_key = match conn.derive_key_for_generation(generation) {
+            Some(k) => k,
+            None => return failure(ResultCode::CryptoError, None)
+        };
+
+        // a generation bump resets the peer's nonce counter to 0, so the replay window only
+        // applies as-is while we're still within the connection's current generation
+        if generation == conn.get_generation() && !conn.check_replay_window(counter) {
+            return failure(ResultCode::CryptoError, None)
+        }
+
+        let data = match reactive_crypto::decrypt(ciphertext, &candidate_key, &u64_to_data(counter), conn.get_encryption()) {
            Ok(d) => d,
            Err(_) => return failure(ResultCode::CryptoError, None)
         };
 
-        conn.increment_nonce();
+        // only now that the packet has authenticated do we commit the generation/key advance
+        // and the replay window, in that order: the generation commit resets the window, and
+        // the counter below is then recorded against the (possibly just-reset) window
+        if generation > conn.get_generation() {
+            conn.commit_generation(generation, candidate_key);
+        }
+        conn.commit_replay_window(counter);
+
+        audit_log::append(conn_id, audit_log::Direction::Input, generation, counter, &data);
+
         let index = &conn.get_index();
         drop(map); // fix: if the input calls an output, the CONNECTIONS map has to be free
 
@@ -216,9 +1039,10 @@ pub mod authentic_execution {
         let connections = map.iter_mut().filter(|(_, v)| v.get_index() == index);
 
         for (conn_id, conn) in connections {
-            let nonce = conn.get_nonce();
-            let payload = match reactive_crypto::encrypt(data, conn.get_key(),
-                                            &u16_to_data(nonce), conn.get_encryption()) {
+            let counter = conn.next_counter(); // may rekey and reset the counter first
+            let generation = conn.get_generation();
+            let ciphertext = match reactive_crypto::encrypt(data, conn.get_key(),
+                                            &u64_to_data(counter), conn.get_encryption()) {
                Ok(p) => p,
                Err(e) => {
                    error!(&format!("{}", e));
@@ -226,43 +1050,107 @@ pub mod authentic_execution {
                }
             };
 
-            conn.increment_nonce();
+            // prefix the generation and counter used as the AEAD nonce so the receiver can
+            // validate it against its sliding window and follow along across rekeys
+            let mut payload = Vec::with_capacity(2 + 8 + ciphertext.len());
+            payload.extend_from_slice(&u16_to_data(generation));
+            payload.extend_from_slice(&u64_to_data(counter));
+            payload.extend(ciphertext);
+
+            audit_log::append(*conn_id, audit_log::Direction::Output, generation, counter, data);
+
             send_to_em(*conn_id, payload);
         }
     }
 
-    /// Send the output payload to the event manager, which will forward it to the input connected to the `index` output
-    fn send_to_em(conn_id : u16, mut data : Vec<u8>) {
-        thread::spawn(move || {
-            let addr = format!("127.0.0.1:{}", *EM_PORT);
+    // Caps how many outputs can be queued for the EM at once. `handle_output` holds the
+    // CONNECTIONS lock while it enqueues, so a bounded channel that blocks on a full queue would
+    // stall every other connection instead of just this one; we track the queue length ourselves
+    // and drop (rather than block on) new outputs once the EM has been unreachable long enough
+    // to back up this many of them.
+    const EM_QUEUE_CAPACITY : usize = 4096;
 
-            debug!(&format!("Sending output with conn ID {} to EM", conn_id));
+    /// Enqueue the output payload for the event manager, which will forward it to the input
+    /// connected to the `index` output. The actual delivery happens on the persistent EM sender
+    /// thread, which preserves send order per connection (nonces are sequential). If the EM has
+    /// been unreachable long enough for `EM_QUEUE_CAPACITY` outputs to back up, the output is
+    /// dropped rather than queued indefinitely.
+    fn send_to_em(conn_id : u16, data : Vec<u8>) {
+        let reserved = EM_QUEUE_LEN.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            if n < EM_QUEUE_CAPACITY { Some(n + 1) } else { None }
+        });
+
+        if reserved.is_err() {
+            error!(&format!("EM send queue is full ({} items); dropping output for conn ID {}", EM_QUEUE_CAPACITY, conn_id));
+            return;
+        }
+
+        debug!(&format!("Queueing output with conn ID {} for the EM", conn_id));
+
+        if let Err(e) = EM_SENDER.lock().unwrap().send((conn_id, data)) {
+            EM_QUEUE_LEN.fetch_sub(1, Ordering::SeqCst);
+            error!(&format!("EM sender thread is gone: {}", e));
+        }
+    }
+
+    /// Runs on the single long-lived EM sender thread: pulls `(conn_id, payload)` pairs off the
+    /// queue in order and writes them to one persistent `TcpStream`, reconnecting with capped
+    /// exponential backoff whenever the connection to the EM drops.
+    fn em_sender_loop(rx : std::sync::mpsc::Receiver<(u16, Vec<u8>)>) {
+        let mut stream : Option<TcpStream> = None;
+
+        for (conn_id, mut data) in rx {
+            EM_QUEUE_LEN.fetch_sub(1, Ordering::SeqCst);
 
             let data_len = data.len();
             if data_len > 65531 {
-                    error!("Data is too big. Aborting");
-                    return;
+                error!("Data is too big. Aborting");
+                continue;
             }
 
             let mut payload = Vec::with_capacity(data_len + 2);
             payload.extend_from_slice(&conn_id.to_be_bytes());
             payload.append(&mut data);
 
-            let mut stream = match TcpStream::connect(addr) {
-                Ok(s) => s,
-                Err(_) => {
-                    error!("Cannot connect to EM");
-                    return;
+            let cmd = CommandMessage::new(CommandCode::ModuleOutput, Some(payload));
+            let mut backoff = std::time::Duration::from_millis(100);
+
+            loop {
+                if stream.is_none() {
+                    stream = connect_to_em();
                 }
-            };
-            debug!("Connected to EM");
 
-            let cmd = CommandMessage::new(CommandCode::ModuleOutput, Some(payload));
+                if let Some(s) = stream.as_mut() {
+                    match reactive_net::write_command(s, &cmd) {
+                        Ok(_) => break,
+                        Err(e) => {
+                            error!(&format!("{}", e));
+                            stream = None;
+                        }
+                    }
+                }
 
-            if let Err(e) = reactive_net::write_command(&mut stream, &cmd) {
-                error!(&format!("{}", e));
+                if stream.is_none() {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(std::time::Duration::from_secs(5));
+                }
             }
-            });
+        }
+    }
+
+    fn connect_to_em() -> Option<TcpStream> {
+        let addr = format!("127.0.0.1:{}", *EM_PORT);
+
+        match TcpStream::connect(&addr) {
+            Ok(s) => {
+                debug!("Connected to EM");
+                Some(s)
+            },
+            Err(_) => {
+                error!("Cannot connect to EM");
+                None
+            }
+        }
     }
 
     // Variables: connections. Contains, for each connection, key, nonce, and handler index
@@ -270,6 +1158,63 @@ pub mod authentic_execution {
         static ref CONNECTIONS: Mutex<HashMap<u16, connection::Connection>> = {
             Mutex::new(HashMap::new())
         };
+        // Handshakes this module has initiated and is waiting on a response for, keyed by conn_id
+        static ref PENDING_HANDSHAKES: Mutex<HashMap<u16, handshake::PendingHandshake>> = {
+            Mutex::new(HashMap::new())
+        };
+        // Queue for the single persistent connection to the EM; `handle_output` only ever enqueues
+        static ref EM_SENDER: Mutex<std::sync::mpsc::Sender<(u16, Vec<u8>)>> = {
+            let (tx, rx) = std::sync::mpsc::channel();
+            thread::spawn(move || em_sender_loop(rx));
+            Mutex::new(tx)
+        };
+    }
+
+    // Tracks how many items are currently sitting in `EM_SENDER`'s queue, so `send_to_em` can
+    // enforce `EM_QUEUE_CAPACITY` without blocking (the channel itself is unbounded).
+    lazy_static! {
+        static ref EM_QUEUE_LEN: AtomicUsize = AtomicUsize::new(0);
+        // Tamper-evident log of every accepted input and emitted output, for attestation
+        static ref AUDIT_LOG: Mutex<audit_log::MerkleMountainRange> = {
+            Mutex::new(audit_log::MerkleMountainRange::new())
+        };
+    }
+
+    // Builds this module's X25519 identity from the trust configuration generated into `__run`.
+    // Returns `Err` rather than panicking on malformed config, consistent with how `set_key`
+    // handles the same base64-decode failure mode -- a panic here would also permanently poison
+    // the `IDENTITY` lazy_static below, taking down every future handshake call for the life of
+    // the process instead of just failing the one bad request.
+    fn build_identity() -> Result<handshake::Identity, ()> {
+        let trust = if *TRUST_MODE == 0 {
+            let decoded = base64::decode(&*SHARED_SECRET).map_err(|_| ())?;
+            if decoded.len() != 32 {
+                return Err(());
+            }
+            let mut secret = [0u8; 32];
+            secret.copy_from_slice(&decoded);
+            handshake::TrustConfig::SharedSecret(secret)
+        } else {
+            let mut trusted = Vec::with_capacity(TRUSTED_PEER_KEYS.len());
+            for k in TRUSTED_PEER_KEYS.iter() {
+                let decoded = base64::decode(k).map_err(|_| ())?;
+                if decoded.len() != 32 {
+                    return Err(());
+                }
+                let mut pub_key = [0u8; 32];
+                pub_key.copy_from_slice(&decoded);
+                trusted.push(pub_key);
+            }
+            handshake::TrustConfig::ExplicitTrust(trusted)
+        };
+
+        Ok(handshake::Identity::new(trust))
+    }
+
+    // This module's X25519 identity, used by the public-key handshake entrypoints. Built from the
+    // trust configuration generated into `__run`, so the EM never sees a plaintext session key.
+    lazy_static! {
+        static ref IDENTITY: Result<handshake::Identity, ()> = build_identity();
     }
 
     // Constants: Module's key, ID, Inputs, Outputs
@@ -289,6 +1234,11 @@ pub mod authentic_execution {
             m.insert(0, set_key_wrapper as fn(&[u8]) -> ResultMessage);
             m.insert(1, handle_input_wrapper as fn(&[u8]) -> ResultMessage);
     		m.insert(2, crate::press_button as fn(&[u8]) -> ResultMessage);
+            m.insert(3, handshake_init_wrapper as fn(&[u8]) -> ResultMessage);
+            m.insert(4, handshake_respond_wrapper as fn(&[u8]) -> ResultMessage);
+            m.insert(5, handshake_finalize_wrapper as fn(&[u8]) -> ResultMessage);
+            m.insert(6, audit_root_wrapper as fn(&[u8]) -> ResultMessage);
+            m.insert(7, audit_proof_wrapper as fn(&[u8]) -> ResultMessage);
 
             m
         };